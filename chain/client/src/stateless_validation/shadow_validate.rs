@@ -1,18 +1,171 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
 use std::time::Instant;
 
 use near_chain::types::{RuntimeStorageConfig, StorageDataSource};
 use near_chain::{Block, BlockHeader};
 use near_chain_primitives::Error;
-use near_primitives::sharding::{ShardChunk, ShardChunkHeader};
-use near_primitives::stateless_validation::EncodedChunkStateWitness;
+use near_primitives::sharding::{ChunkHash, ShardChunk, ShardChunkHeader};
+use near_primitives::stateless_validation::{EncodedChunkStateWitness, EpochTransitionProof};
+use near_primitives::types::{BlockHeight, EpochId, ShardId};
 
 use crate::stateless_validation::chunk_validator::{
     pre_validate_chunk_state_witness, validate_chunk_state_witness, validate_prepared_transactions,
-    MainStateTransitionCache,
+    MainStateTransitionCache, PreValidationOutput,
 };
 use crate::{metrics, Client};
 
+/// Range of witness wire-format versions this build can decode. Widen the upper bound when
+/// rolling out a new format so nodes keep interoperating during the upgrade window, and drop
+/// the lower bound only once no node in the fleet can still produce it.
+pub const SUPPORTED_WITNESS_VERSIONS: std::ops::RangeInclusive<u16> = 1..=1;
+
+/// Rejects a witness format version outside of [`SUPPORTED_WITNESS_VERSIONS`] with a distinct
+/// error instead of the generic decode failure this used to surface as, so callers (and metrics
+/// dashboards) can tell "this witness used a version we no longer/don't yet speak" apart from
+/// "this witness is garbage".
+fn check_supported_witness_version(version: u16) -> Result<(), Error> {
+    if SUPPORTED_WITNESS_VERSIONS.contains(&version) {
+        Ok(())
+    } else {
+        Err(Error::InvalidChunkStateWitness(format!(
+            "unsupported chunk state witness format version {version}, supported range is {SUPPORTED_WITNESS_VERSIONS:?}"
+        )))
+    }
+}
+
+/// Codec ids this build can decompress. `encoded_witness.decode()` round-tripping successfully
+/// only proves the local encoder and decoder agree with each other — it says nothing about
+/// whether the codec id written into the header is one this binary knows. Checking the codec id
+/// itself, independent of whether decode happens to succeed, rejects an unknown codec id with
+/// its own error.
+const SUPPORTED_WITNESS_CODECS: [u8; 3] = [0, 1, 2];
+
+fn check_supported_witness_codec(codec_id: u8) -> Result<(), Error> {
+    if SUPPORTED_WITNESS_CODECS.contains(&codec_id) {
+        Ok(())
+    } else {
+        Err(Error::InvalidChunkStateWitness(format!(
+            "unsupported chunk state witness compression codec id {codec_id}"
+        )))
+    }
+}
+
+/// A dedicated shadow validation worker pool plus the in-flight counter `shadow_validate_chunk`
+/// uses to decide whether to apply backpressure. One of these exists per live `Client` instance
+/// (keyed by the `Client`'s own address, see [`Client::shadow_validation_pool`]) rather than per
+/// `shadow_validation_num_threads` value, so two `Client`s that happen to share a config value —
+/// the common case, e.g. both using the default — don't silently share a pool and an in-flight
+/// counter, the way an integration test spinning up several nodes in one process would otherwise
+/// hit. The `SHADOW_CHUNK_VALIDATION_*` metrics are process-wide and not labeled per `Client`.
+struct ShadowValidationPool {
+    /// `None` if this build's rayon couldn't allocate the requested threads; `spawn` then falls
+    /// back to the global rayon pool so shadow validation degrades gracefully instead of
+    /// panicking the whole node over a background diagnostics feature.
+    pool: Option<rayon::ThreadPool>,
+    in_flight: AtomicUsize,
+}
+
+impl ShadowValidationPool {
+    fn spawn(self: &Arc<Self>, job: impl FnOnce() + Send + 'static) {
+        let this = self.clone();
+        let job = move || {
+            job();
+            this.in_flight.fetch_sub(1, Ordering::Relaxed);
+            metrics::SHADOW_CHUNK_VALIDATION_IN_FLIGHT.dec();
+        };
+        match &self.pool {
+            Some(pool) => pool.spawn(job),
+            None => rayon::spawn(job),
+        }
+    }
+}
+
+/// Keyed by `Client` identity (see [`Client::shadow_validation_pool`]), not by config value: two
+/// `Client`s are two distinct live objects even when constructed with identical `ClientConfig`s,
+/// so keying on the `Client`'s own address keeps them from ever colliding, unlike keying on a
+/// config value both instances happen to share.
+static SHADOW_VALIDATION_POOLS: OnceLock<Mutex<HashMap<usize, Arc<ShadowValidationPool>>>> =
+    OnceLock::new();
+
+fn build_shadow_validation_pool(num_threads: usize) -> ShadowValidationPool {
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(num_threads)
+        .thread_name(|idx| format!("shadow-validation-{idx}"))
+        .build()
+        .map_err(|err| {
+            tracing::error!(
+                target: "client",
+                ?err,
+                num_threads,
+                "failed to build shadow validation thread pool, falling back to the shared rayon \
+                 pool"
+            );
+        })
+        .ok();
+    ShadowValidationPool { pool, in_flight: AtomicUsize::new(0) }
+}
+
+/// One witness captured off the shadow-validation path, identified by the chunk it was produced
+/// for, together with the pre-validation result needed to re-run `validate_chunk_state_witness`
+/// against it later without touching the live chain.
+#[derive(Clone)]
+pub struct ArchivedChunkStateWitness {
+    pub shard_id: ShardId,
+    pub height: BlockHeight,
+    pub chunk_hash: ChunkHash,
+    pub encoded_witness: EncodedChunkStateWitness,
+    pub pre_validation_result: PreValidationOutput,
+}
+
+/// Whether `shadow_validate_block_chunks` should produce an epoch transition proof for this
+/// block, independent of whether any shard happened to produce a new chunk at this boundary.
+/// Missing chunks are routine; gating proof production on the new-chunk loop instead of on
+/// `is_next_block_epoch_start` alone would silently skip the proof at any epoch boundary where
+/// every shard happened to be missing its new chunk, leaving a permanent gap in the from-genesis
+/// validator-set transition chain.
+fn should_produce_epoch_transition_proof(is_next_block_epoch_start: bool) -> bool {
+    is_next_block_epoch_start
+}
+
+/// Which store `get_epoch_transition_proof` should read from for a given `epoch_id`. The genesis
+/// epoch is never produced by `produce_and_store_epoch_transition_proof` — there is no previous
+/// epoch for it to transition from — so it needs its own lookup instead of the regular per-epoch
+/// store.
+#[derive(Debug, PartialEq, Eq)]
+enum EpochTransitionProofLookup {
+    Genesis,
+    Epoch,
+}
+
+fn epoch_transition_proof_lookup(epoch_id: &EpochId) -> EpochTransitionProofLookup {
+    if epoch_id == &EpochId::default() {
+        EpochTransitionProofLookup::Genesis
+    } else {
+        EpochTransitionProofLookup::Epoch
+    }
+}
+
 impl Client {
+    /// This `Client`'s dedicated shadow validation pool, built on first use and reused for the
+    /// rest of this instance's lifetime. Keyed by this `Client`'s own address rather than by
+    /// `shadow_validation_num_threads` so that two `Client`s sharing a config value (the common
+    /// case) never share a pool or in-flight counter with each other. The queue-depth/dropped
+    /// metrics are process-wide, so they are shared.
+    fn shadow_validation_pool(&self) -> Arc<ShadowValidationPool> {
+        let key = self as *const Client as usize;
+        let pools = SHADOW_VALIDATION_POOLS.get_or_init(|| Mutex::new(HashMap::new()));
+        pools
+            .lock()
+            .unwrap()
+            .entry(key)
+            .or_insert_with(|| {
+                Arc::new(build_shadow_validation_pool(self.config.shadow_validation_num_threads))
+            })
+            .clone()
+    }
+
     // Temporary feature to make node produce state witness for every chunk in every processed block
     // and then self-validate it.
     pub(crate) fn shadow_validate_block_chunks(&mut self, block: &Block) -> Result<(), Error> {
@@ -23,6 +176,20 @@ impl Client {
         tracing::debug!(target: "client", ?block_hash, "shadow validation for block chunks");
         let prev_block = self.chain.get_block(block.header().prev_hash())?;
         let prev_block_chunks = prev_block.chunks();
+        let is_epoch_start =
+            self.epoch_manager.is_next_block_epoch_start(block.header().prev_hash())?;
+        if should_produce_epoch_transition_proof(is_epoch_start) {
+            if let Err(err) =
+                self.produce_and_store_epoch_transition_proof(block, prev_block.header())
+            {
+                tracing::error!(
+                    target: "client",
+                    ?err,
+                    ?block_hash,
+                    "failed to produce shadow epoch transition proof"
+                );
+            }
+        }
         for chunk in
             block.chunks().iter().filter(|chunk| chunk.is_new_chunk(block.header().height()))
         {
@@ -44,6 +211,61 @@ impl Client {
         Ok(())
     }
 
+    /// Builds the validator-set transition at this epoch boundary (both epoch ids, the epoch info
+    /// of the previous and the new epoch, and the header of the new epoch's first block) and
+    /// persists it keyed by the new epoch id. No chunk state witness is included in the proof.
+    /// Lets a syncing or light client verify the chain of validator-set transitions from genesis
+    /// without replaying every chunk.
+    fn produce_and_store_epoch_transition_proof(
+        &mut self,
+        block: &Block,
+        prev_block_header: &BlockHeader,
+    ) -> Result<(), Error> {
+        let epoch_id = block.header().epoch_id();
+        let prev_epoch_id = prev_block_header.epoch_id();
+        let proof = EpochTransitionProof {
+            epoch_id: *epoch_id,
+            prev_epoch_id: *prev_epoch_id,
+            prev_epoch_validator_info: self.epoch_manager.get_epoch_info(prev_epoch_id)?,
+            next_epoch_validator_info: self.epoch_manager.get_epoch_info(epoch_id)?,
+            block_header: block.header().clone(),
+        };
+        self.chain.chain_store.save_epoch_transition_proof(epoch_id, &proof)?;
+        Ok(())
+    }
+
+    /// Fetches the stored epoch transition proof for `epoch_id`, falling back to the genesis
+    /// transition for the genesis epoch since that one is never produced by shadow validation.
+    pub fn get_epoch_transition_proof(
+        &self,
+        epoch_id: &EpochId,
+    ) -> Result<Option<EpochTransitionProof>, Error> {
+        match epoch_transition_proof_lookup(epoch_id) {
+            EpochTransitionProofLookup::Genesis => {
+                self.chain.chain_store.get_genesis_epoch_transition_proof()
+            }
+            EpochTransitionProofLookup::Epoch => {
+                self.chain.chain_store.get_epoch_transition_proof(epoch_id)
+            }
+        }
+    }
+
+    /// Same as [`Client::get_epoch_transition_proof`] but reaches into cold/archival storage for
+    /// epochs that have since been garbage-collected from hot storage.
+    pub fn get_ancient_epoch_transition_proof(
+        &self,
+        epoch_id: &EpochId,
+    ) -> Result<Option<EpochTransitionProof>, Error> {
+        match epoch_transition_proof_lookup(epoch_id) {
+            EpochTransitionProofLookup::Genesis => {
+                self.chain.chain_store.get_genesis_epoch_transition_proof()
+            }
+            EpochTransitionProofLookup::Epoch => {
+                self.chain.chain_store.get_ancient_epoch_transition_proof(epoch_id)
+            }
+        }
+    }
+
     fn shadow_validate_chunk(
         &mut self,
         prev_block_header: &BlockHeader,
@@ -93,18 +315,39 @@ impl Client {
             let encode_timer = metrics::CHUNK_STATE_WITNESS_ENCODE_TIME
                 .with_label_values(&[shard_id_label.as_str()])
                 .start_timer();
-            let (encoded_witness, raw_witness_size) = EncodedChunkStateWitness::encode(&witness)?;
+            let compression_codec = self.config.state_witness_compression_codec;
+            let (encoded_witness, raw_witness_size) =
+                EncodedChunkStateWitness::encode_with_codec(&witness, compression_codec)?;
             encode_timer.observe_duration();
             metrics::record_witness_size_metrics(
                 raw_witness_size,
                 encoded_witness.size_bytes(),
                 &witness,
             );
+            metrics::CHUNK_STATE_WITNESS_FORMAT_VERSION
+                .with_label_values(&[shard_id_label.as_str(), &witness.format_version().to_string()])
+                .inc();
+            // `raw_witness_size` is pre-compression, `size_bytes()` is what actually goes over the
+            // wire/into storage, so a ratio close to 1.0 means the codec isn't pulling its weight.
+            if raw_witness_size > 0 {
+                metrics::CHUNK_STATE_WITNESS_COMPRESSION_RATIO
+                    .with_label_values(&[shard_id_label.as_str()])
+                    .set(encoded_witness.size_bytes() as f64 / raw_witness_size as f64);
+            }
+            // Checked up front, independent of the decode below: rejects a witness whose header
+            // names a codec id this binary doesn't know how to decompress (e.g. from the configured
+            // codec), rather than relying on the round-trip below, which only exercises this
+            // node's own encoder/decoder pair against each other.
+            check_supported_witness_codec(encoded_witness.codec_id())?;
             let decode_timer = metrics::CHUNK_STATE_WITNESS_DECODE_TIME
                 .with_label_values(&[shard_id_label.as_str()])
                 .start_timer();
-            encoded_witness.decode()?;
+            // The decode-after-encode round trip still catches corruption within a codec we do
+            // support, so a bad encoder/decoder pairing fails loudly during shadow validation
+            // instead of silently producing a witness nobody can read.
+            let (_, decoded_version) = encoded_witness.decode()?;
             decode_timer.observe_duration();
+            check_supported_witness_version(decoded_version)?;
             (encoded_witness, raw_witness_size)
         };
         let pre_validation_start = Instant::now();
@@ -123,9 +366,45 @@ impl Client {
             pre_validation_elapsed = ?pre_validation_start.elapsed(),
             "completed shadow chunk pre-validation"
         );
+        if self.config.save_witness_archive {
+            // Archived separately from `save_latest_chunk_state_witness`: this is a bounded
+            // per-shard ring buffer keyed by (shard_id, height, chunk_hash) rather than a single
+            // "latest" slot, so operators can replay a run of recent failures offline instead of
+            // only ever seeing the last one. Persisted through `chain_store` (on disk), not held
+            // in process memory, so the archive an operator is chasing a
+            // `SHADOW_CHUNK_VALIDATION_FAILED_TOTAL` bump with is still there after the node that
+            // hit it restarts or crashes.
+            self.chain.chain_store.save_chunk_state_witness_to_archive(
+                shard_id,
+                self.config.witness_archive_capacity_per_shard,
+                ArchivedChunkStateWitness {
+                    shard_id,
+                    height: chunk_header.height_created(),
+                    chunk_hash: chunk_hash.clone(),
+                    encoded_witness: encoded_witness.clone(),
+                    pre_validation_result: pre_validation_result.clone(),
+                },
+            )?;
+        }
+        let pool = self.shadow_validation_pool();
+        let in_flight = pool.in_flight.load(Ordering::Relaxed);
+        metrics::SHADOW_CHUNK_VALIDATION_QUEUE_DEPTH.set(in_flight as i64);
+        if in_flight >= self.config.shadow_validation_max_queue {
+            metrics::SHADOW_CHUNK_VALIDATION_DROPPED_TOTAL.inc();
+            tracing::warn!(
+                target: "client",
+                shard_id,
+                ?chunk_hash,
+                in_flight,
+                "shadow validation pool is saturated, dropping chunk validation task"
+            );
+            return Ok(());
+        }
+        pool.in_flight.fetch_add(1, Ordering::Relaxed);
+        metrics::SHADOW_CHUNK_VALIDATION_IN_FLIGHT.inc();
         let epoch_manager = self.epoch_manager.clone();
         let runtime_adapter = self.runtime_adapter.clone();
-        rayon::spawn(move || {
+        pool.spawn(move || {
             let validation_start = Instant::now();
             match validate_chunk_state_witness(
                 witness,
@@ -157,4 +436,130 @@ impl Client {
         });
         Ok(())
     }
+
+    /// Returns the archived shadow-validation witnesses for `shard_id`, most recent first. Empty
+    /// unless `save_witness_archive` is enabled in `ClientConfig`, and bounded at
+    /// `witness_archive_capacity_per_shard` entries per shard regardless of how many have been
+    /// produced over the node's lifetime. Reads through `chain_store`, so this survives a restart
+    /// of the node that produced the entries.
+    pub fn iter_chunk_state_witness_archive(
+        &self,
+        shard_id: ShardId,
+    ) -> Result<Vec<ArchivedChunkStateWitness>, Error> {
+        self.chain.chain_store.get_chunk_state_witness_archive(shard_id)
+    }
+
+    /// Re-runs `validate_chunk_state_witness` over every witness archived for `shard_id`, without
+    /// touching the live chain. Gives operators a deterministic "snap and restore" harness to
+    /// reproduce a `SHADOW_CHUNK_VALIDATION_FAILED_TOTAL` bump reported after the fact.
+    pub fn replay_chunk_state_witness_archive(&self, shard_id: ShardId) -> Result<(), Error> {
+        for archived in self.iter_chunk_state_witness_archive(shard_id)? {
+            let ArchivedChunkStateWitness {
+                pre_validation_result, height, chunk_hash, encoded_witness, ..
+            } = archived;
+            let witness = match encoded_witness.decode() {
+                Ok((witness, _)) => witness,
+                Err(err) => {
+                    tracing::error!(
+                        target: "client",
+                        ?err,
+                        shard_id,
+                        height,
+                        ?chunk_hash,
+                        "archived witness decode failed"
+                    );
+                    continue;
+                }
+            };
+            if let Err(err) = validate_chunk_state_witness(
+                witness,
+                pre_validation_result,
+                self.epoch_manager.as_ref(),
+                self.runtime_adapter.as_ref(),
+                &MainStateTransitionCache::default(),
+            ) {
+                tracing::error!(
+                    target: "client",
+                    ?err,
+                    shard_id,
+                    height,
+                    ?chunk_hash,
+                    "archived witness replay failed"
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_supported_witness_versions() {
+        for version in SUPPORTED_WITNESS_VERSIONS {
+            assert!(check_supported_witness_version(version).is_ok());
+        }
+    }
+
+    #[test]
+    fn rejects_witness_versions_outside_supported_range() {
+        let err = check_supported_witness_version(*SUPPORTED_WITNESS_VERSIONS.end() + 1)
+            .expect_err("version above the supported range must be rejected");
+        assert!(matches!(err, Error::InvalidChunkStateWitness(_)));
+    }
+
+    #[test]
+    fn build_shadow_validation_pool_succeeds_for_a_reasonable_thread_count() {
+        let pool = build_shadow_validation_pool(2);
+        assert!(pool.pool.is_some());
+        assert_eq!(pool.in_flight.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn accepts_supported_witness_codecs() {
+        for codec_id in SUPPORTED_WITNESS_CODECS {
+            assert!(check_supported_witness_codec(codec_id).is_ok());
+        }
+    }
+
+    #[test]
+    fn rejects_unsupported_witness_codec() {
+        let err = check_supported_witness_codec(255)
+            .expect_err("an unknown codec id must be rejected independently of decode");
+        assert!(matches!(err, Error::InvalidChunkStateWitness(_)));
+    }
+
+    #[test]
+    fn shadow_validation_pool_in_flight_counter_tracks_increments_and_decrements() {
+        let pool = build_shadow_validation_pool(3);
+        let before = pool.in_flight.load(Ordering::Relaxed);
+        pool.in_flight.fetch_add(1, Ordering::Relaxed);
+        assert_eq!(pool.in_flight.load(Ordering::Relaxed), before + 1);
+        pool.in_flight.fetch_sub(1, Ordering::Relaxed);
+        assert_eq!(pool.in_flight.load(Ordering::Relaxed), before);
+    }
+
+    #[test]
+    fn epoch_transition_proof_is_produced_on_every_epoch_start_block_regardless_of_new_chunks() {
+        // Guards against gating production inside the loop over new chunks, under which an
+        // epoch-start block where every shard is missing its new chunk would produce no proof.
+        assert!(should_produce_epoch_transition_proof(true));
+        assert!(!should_produce_epoch_transition_proof(false));
+    }
+
+    #[test]
+    fn epoch_transition_proof_lookup_uses_genesis_store_for_default_epoch_id() {
+        assert_eq!(
+            epoch_transition_proof_lookup(&EpochId::default()),
+            EpochTransitionProofLookup::Genesis
+        );
+    }
+
+    #[test]
+    fn epoch_transition_proof_lookup_uses_regular_store_for_non_genesis_epoch() {
+        let epoch_id = EpochId(near_primitives::hash::CryptoHash::hash_bytes(b"some-epoch"));
+        assert_eq!(epoch_transition_proof_lookup(&epoch_id), EpochTransitionProofLookup::Epoch);
+    }
 }